@@ -11,11 +11,23 @@ use std::sync::Arc;
 use common::AOMCodec;
 use data::frame::{Frame, VideoInfo};
 use data::frame::{PictureType, new_default_frame};
-use data::pixel::formats::YUV420;
+use data::pixel::formats::{
+    YUV420, YUV422, YUV444, YUV420_10, YUV422_10, YUV444_10, YUV420_12, YUV422_12, YUV444_12,
+};
+use encoder::ColorConfig;
 
-fn frame_from_img(img: aom_image_t) -> Frame {
-    let f = match img.fmt {
-        aom_img_fmt_AOM_IMG_FMT_I420 => YUV420,
+pub(crate) fn frame_from_img(img: aom_image_t) -> (Frame, ColorConfig) {
+    let base_fmt = img.fmt & !aom_img_fmt_AOM_IMG_FMT_HIGHBITDEPTH;
+    let f = match (base_fmt, img.bit_depth) {
+        (aom_img_fmt_AOM_IMG_FMT_I420, 8) => YUV420,
+        (aom_img_fmt_AOM_IMG_FMT_I422, 8) => YUV422,
+        (aom_img_fmt_AOM_IMG_FMT_I444, 8) => YUV444,
+        (aom_img_fmt_AOM_IMG_FMT_I420, 10) => YUV420_10,
+        (aom_img_fmt_AOM_IMG_FMT_I422, 10) => YUV422_10,
+        (aom_img_fmt_AOM_IMG_FMT_I444, 10) => YUV444_10,
+        (aom_img_fmt_AOM_IMG_FMT_I420, 12) => YUV420_12,
+        (aom_img_fmt_AOM_IMG_FMT_I422, 12) => YUV422_12,
+        (aom_img_fmt_AOM_IMG_FMT_I444, 12) => YUV444_12,
         _ => panic!("TODO: support more pixel formats"),
     };
     let v = VideoInfo {
@@ -31,7 +43,23 @@ fn frame_from_img(img: aom_image_t) -> Frame {
     let linesize = img.stride.iter().map(|l| *l as usize);
 
     f.copy_from_raw_parts(src, linesize);
-    f
+    (f, ColorConfig::from_image(&img))
+}
+
+/// Stream-level info about a compressed frame, peeked without decoding it
+///
+/// Returned by [`AV1Decoder::peek_stream_info`], which a caller can use to
+/// decide whether a given frame is safe to drop over a lossy or
+/// bandwidth-constrained transport: only [`is_keyframe`] frames must always
+/// be decoded, since every later frame may reference one.
+///
+/// [`AV1Decoder::peek_stream_info`]: struct.AV1Decoder.html#method.peek_stream_info
+/// [`is_keyframe`]: #structfield.is_keyframe
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub width: u32,
+    pub height: u32,
+    pub is_keyframe: bool,
 }
 
 /// AO1 Decoder
@@ -44,6 +72,18 @@ pub struct AV1Decoder<T> {
 impl<T> AV1Decoder<T> {
     /// Create a new decoder
     pub fn new() -> Result<AV1Decoder<T>, aom_codec_err_t> {
+        AV1Decoder::new_with_flags(0)
+    }
+
+    /// Create a new decoder, passing custom initialization flags
+    ///
+    /// `flags` matches libaom's `aom_codec_dec_init_ver` flags, e.g.
+    /// `AOM_CODEC_USE_FRAME_THREADING` to enable frame-parallel decoding,
+    /// which is useful alongside [`last_frame_corrupted`] when decoding
+    /// over a lossy transport.
+    ///
+    /// [`last_frame_corrupted`]: #method.last_frame_corrupted
+    pub fn new_with_flags(flags: u32) -> Result<AV1Decoder<T>, aom_codec_err_t> {
         let mut dec = AV1Decoder {
             ctx: unsafe { uninitialized() },
             iter: ptr::null(),
@@ -56,7 +96,7 @@ impl<T> AV1Decoder<T> {
                 &mut dec.ctx as *mut aom_codec_ctx,
                 aom_codec_av1_dx(),
                 &cfg as *const aom_codec_dec_cfg_t,
-                0,
+                flags,
                 AOM_DECODER_ABI_VERSION as i32,
             )
         };
@@ -66,6 +106,40 @@ impl<T> AV1Decoder<T> {
         }
     }
 
+    /// Inspect the next frame's header without decoding it
+    ///
+    /// Lets a caller selectively drop non-reference frames over a lossy or
+    /// bandwidth-constrained transport: if [`StreamInfo::is_keyframe`] is
+    /// `false`, the frame may be dropped by simply not passing it to
+    /// [`decode`]; a keyframe must always be decoded, since every following
+    /// frame may depend on it.
+    ///
+    /// It calls `aom_codec_peek_stream_info`.
+    ///
+    /// [`StreamInfo::is_keyframe`]: struct.StreamInfo.html#structfield.is_keyframe
+    /// [`decode`]: #method.decode
+    pub fn peek_stream_info(data: &[u8]) -> Result<StreamInfo, aom_codec_err_t> {
+        let mut si: aom_codec_stream_info_t = unsafe { zeroed() };
+
+        let ret = unsafe {
+            aom_codec_peek_stream_info(
+                aom_codec_av1_dx(),
+                data.as_ptr(),
+                data.len(),
+                &mut si,
+            )
+        };
+
+        match ret {
+            aom_codec_err_t_AOM_CODEC_OK => Ok(StreamInfo {
+                width: si.w,
+                height: si.h,
+                is_keyframe: si.is_kf != 0,
+            }),
+            _ => Err(ret),
+        }
+    }
+
     /// Feed some compressed data to the encoder
     ///
     /// The `data` slice is sent to the decoder alongside the optional
@@ -107,6 +181,55 @@ impl<T> AV1Decoder<T> {
     }
 
 
+    /// Update the decoder parameters after creation
+    ///
+    /// It calls `aom_codec_control_`.
+    pub fn control(&mut self, id: aom_dec_control_id, val: i32) -> Result<(), aom_codec_err_t> {
+        let ret = unsafe { aom_codec_control_(&mut self.ctx, id as i32, val) };
+
+        match ret {
+            aom_codec_err_t_AOM_CODEC_OK => Ok(()),
+            _ => Err(ret),
+        }
+    }
+
+    /// Check whether the last frame returned by [`get_frame`] was
+    /// reconstructed from incomplete or corrupted data
+    ///
+    /// Should be called right after [`get_frame`], before feeding more
+    /// compressed data to the decoder. Useful alongside
+    /// [`new_with_flags`] / frame dropping to tolerate a lossy transport.
+    ///
+    /// It calls `aom_codec_control_` with `AOMD_GET_FRAME_CORRUPTED`.
+    ///
+    /// [`get_frame`]: #method.get_frame
+    /// [`new_with_flags`]: #method.new_with_flags
+    pub fn last_frame_corrupted(&mut self) -> Result<bool, aom_codec_err_t> {
+        let mut corrupted: i32 = 0;
+        let ret = unsafe {
+            aom_codec_control_(
+                &mut self.ctx,
+                aom_dec_control_id_AOMD_GET_FRAME_CORRUPTED as i32,
+                &mut corrupted as *mut i32,
+            )
+        };
+
+        match ret {
+            aom_codec_err_t_AOM_CODEC_OK => Ok(corrupted != 0),
+            _ => Err(ret),
+        }
+    }
+
+    /// Toggle whether the decoder synthesizes film grain
+    ///
+    /// When disabled, `get_frame` returns the reconstructed picture without
+    /// grain applied even if the bitstream carries grain metadata.
+    ///
+    /// It calls `aom_codec_control_` with `AV1D_SET_APPLY_GRAIN`.
+    pub fn set_apply_grain(&mut self, apply: bool) -> Result<(), aom_codec_err_t> {
+        self.control(aom_dec_control_id_AV1D_SET_APPLY_GRAIN, apply as i32)
+    }
+
     /// Notify the decoder to return any pending frame
     ///
     /// The [`get_frame`] method must be called to retrieve the decompressed
@@ -140,7 +263,15 @@ impl<T> AV1Decoder<T> {
     /// Should be called repeatedly until it returns `None`.
     ///
     /// It matches a call to `aom_codec_get_frame`.
-    pub fn get_frame(&mut self) -> Option<(Frame, Option<Box<T>>)> {
+    ///
+    /// Breaking change: this used to return `Option<(Frame, Option<Box<T>>)>`.
+    /// It now also returns, as the middle element of the tuple, the
+    /// [`ColorConfig`] tagged on the decoded image, since
+    /// `Frame`'s `VideoInfo` (defined in an external crate this series does
+    /// not touch) has nowhere to carry color primaries/transfer/matrix/range
+    /// and chroma sample position. Every caller of `get_frame` must add a
+    /// binding for it.
+    pub fn get_frame(&mut self) -> Option<(Frame, ColorConfig, Option<Box<T>>)> {
         let img = unsafe { aom_codec_get_frame(&mut self.ctx, &mut self.iter) };
         mem::forget(img);
 
@@ -154,8 +285,8 @@ impl<T> AV1Decoder<T> {
                 let p : *mut T = unsafe { mem::transmute(im.user_priv) };
                 Some(unsafe { Box::from_raw(p) })
             };
-            let frame = frame_from_img(im);
-            Some((frame, priv_data))
+            let (frame, color) = frame_from_img(im);
+            Some((frame, color, priv_data))
         }
     }
 }
@@ -184,6 +315,10 @@ mod tests {
 
     use super::super::encoder::tests as enc;
     use super::super::encoder::AOMPacket;
+    use super::super::encoder::{
+        ColorConfig, ColorPrimaries, TransferCharacteristics, MatrixCoefficients, ColorRange,
+        ChromaSamplePosition,
+    };
     use data::timeinfo::TimeInfo;
     use data::rational::*;
     #[test]
@@ -220,7 +355,7 @@ mod tests {
                         let _ = d.decode(&pkt.data, None).unwrap();
 
                         // No multiframe expected.
-                        if let Some(f) = d.get_frame() {
+                        if let Some((f, _, _)) = d.get_frame() {
                             out = 1;
                             println!("{:#?}", f);
                         }
@@ -234,4 +369,242 @@ mod tests {
         }
     }
 
+    #[test]
+    fn color_config_round_trips_through_decode() {
+        let w = 800;
+        let h = 600;
+
+        let t = TimeInfo {
+            pts: Some(0),
+            dts: Some(0),
+            duration: Some(1),
+            timebase: Some(Rational64::new(1, 1000)),
+            user_private: None,
+        };
+
+        let mut e = enc::setup(w, h, &t);
+        let mut f = enc::setup_frame(w, h, &t);
+
+        let color = ColorConfig {
+            primaries: ColorPrimaries::BT709,
+            transfer_characteristics: TransferCharacteristics::SRGB,
+            matrix_coefficients: MatrixCoefficients::BT709,
+            range: ColorRange::Full,
+            chroma_sample_position: ChromaSamplePosition::Colocated,
+        };
+        e.set_color_config(&color).unwrap();
+
+        let mut d = AV1Decoder::<()>::new().unwrap();
+        let mut out = 0;
+
+        for i in 0..100 {
+            e.encode(&f).unwrap();
+            f.t.pts = Some(i);
+
+            loop {
+                let p = e.get_packet();
+
+                if p.is_none() {
+                    break;
+                } else {
+                    if let AOMPacket::Packet(ref pkt) = p.unwrap() {
+                        let _ = d.decode(&pkt.data, None).unwrap();
+
+                        // No multiframe expected.
+                        if let Some((_, decoded_color, _)) = d.get_frame() {
+                            out = 1;
+                            assert_eq!(decoded_color, color);
+                        }
+                    }
+                }
+            }
+        }
+
+        if out != 1 {
+            panic!("No frame decoded");
+        }
+    }
+
+    #[test]
+    fn decode_with_apply_grain_disabled() {
+        let w = 800;
+        let h = 600;
+
+        let t = TimeInfo {
+            pts: Some(0),
+            dts: Some(0),
+            duration: Some(1),
+            timebase: Some(Rational64::new(1, 1000)),
+            user_private: None,
+        };
+
+        let mut e = enc::setup(w, h, &t);
+        let mut f = enc::setup_frame(w, h, &t);
+
+        let mut d = AV1Decoder::<()>::new().unwrap();
+        d.set_apply_grain(false).unwrap();
+        let mut out = 0;
+
+        for i in 0..100 {
+            e.encode(&f).unwrap();
+            f.t.pts = Some(i);
+
+            loop {
+                let p = e.get_packet();
+
+                if p.is_none() {
+                    break;
+                } else {
+                    if let AOMPacket::Packet(ref pkt) = p.unwrap() {
+                        let _ = d.decode(&pkt.data, None).unwrap();
+
+                        // No multiframe expected.
+                        if d.get_frame().is_some() {
+                            out = 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        if out != 1 {
+            panic!("No frame decoded");
+        }
+    }
+
+    #[test]
+    fn last_frame_corrupted_is_false_on_clean_decode() {
+        let w = 800;
+        let h = 600;
+
+        let t = TimeInfo {
+            pts: Some(0),
+            dts: Some(0),
+            duration: Some(1),
+            timebase: Some(Rational64::new(1, 1000)),
+            user_private: None,
+        };
+
+        let mut e = enc::setup(w, h, &t);
+        let mut f = enc::setup_frame(w, h, &t);
+
+        let mut d = AV1Decoder::<()>::new().unwrap();
+        let mut out = 0;
+
+        for i in 0..100 {
+            e.encode(&f).unwrap();
+            f.t.pts = Some(i);
+
+            loop {
+                let p = e.get_packet();
+
+                if p.is_none() {
+                    break;
+                } else {
+                    if let AOMPacket::Packet(ref pkt) = p.unwrap() {
+                        let _ = d.decode(&pkt.data, None).unwrap();
+
+                        // No multiframe expected.
+                        if d.get_frame().is_some() {
+                            out = 1;
+                            assert_eq!(d.last_frame_corrupted().unwrap(), false);
+                        }
+                    }
+                }
+            }
+        }
+
+        if out != 1 {
+            panic!("No frame decoded");
+        }
+    }
+
+    #[test]
+    fn peek_stream_info_identifies_keyframe() {
+        let w = 800;
+        let h = 600;
+
+        let t = TimeInfo {
+            pts: Some(0),
+            dts: Some(0),
+            duration: Some(1),
+            timebase: Some(Rational64::new(1, 1000)),
+            user_private: None,
+        };
+
+        let mut e = enc::setup(w, h, &t);
+        let f = enc::setup_frame(w, h, &t);
+
+        e.encode(&f).unwrap();
+
+        let pkt = loop {
+            match e.get_packet() {
+                Some(AOMPacket::Packet(pkt)) => break pkt,
+                Some(_) => continue,
+                None => panic!("no packet produced for the first frame"),
+            }
+        };
+
+        // The very first frame of a sequence is always a keyframe.
+        let info = AV1Decoder::<()>::peek_stream_info(&pkt.data).unwrap();
+        assert_eq!(info.width, w);
+        assert_eq!(info.height, h);
+        assert!(info.is_keyframe);
+    }
+
+    #[test]
+    fn decode_high_bit_depth_444() {
+        use data::frame::MediaKind;
+
+        let w = 200;
+        let h = 200;
+
+        let t = TimeInfo {
+            pts: Some(0),
+            dts: Some(0),
+            duration: Some(1),
+            timebase: Some(Rational64::new(1, 1000)),
+            user_private: None,
+        };
+
+        let mut e = enc::setup_with_format(w, h, &t, &YUV444_10);
+        let mut f = enc::setup_frame_with_format(w, h, &t, &YUV444_10);
+
+        let mut d = AV1Decoder::<()>::new().unwrap();
+        let mut out = 0;
+
+        for i in 0..10 {
+            e.encode(&f).unwrap();
+            f.t.pts = Some(i);
+
+            loop {
+                let p = e.get_packet();
+
+                if p.is_none() {
+                    break;
+                } else {
+                    if let AOMPacket::Packet(ref pkt) = p.unwrap() {
+                        let _ = d.decode(&pkt.data, None).unwrap();
+
+                        // No multiframe expected.
+                        if let Some((f, _, _)) = d.get_frame() {
+                            out = 1;
+                            if let MediaKind::Video(ref v) = f.kind {
+                                assert_eq!(v.width, w as usize);
+                                assert_eq!(v.height, h as usize);
+                                assert_eq!(*v.format, *YUV444_10);
+                            } else {
+                                panic!("decoded frame is not video");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if out != 1 {
+            panic!("No frame decoded");
+        }
+    }
+
 }